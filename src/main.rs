@@ -1,10 +1,35 @@
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use clap::Clap;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
 use std::sync::Arc;
 
 const MEMORY_SIZE: u32 = 1 << 20; // 1MB
 
+// A recoverable emulation fault. Bubbles up out of `Emulator::exec` so a host
+// program can report it and carry on instead of aborting the process.
+#[derive(Debug)]
+enum Error {
+    // Access outside the backing memory, carrying the offending address.
+    Memory(u32),
+    // A ModRM/operand form we do not decode.
+    Decode,
+    // An opcode with no registered instruction.
+    UnknownOpcode(u8),
+    // A software interrupt with no registered trap handler.
+    UnhandledInterrupt(u8),
+}
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::Memory(addr) => write!(f, "memory fault at 0x{:X}", addr),
+            Error::Decode => write!(f, "decode error"),
+            Error::UnknownOpcode(op) => write!(f, "unknown opcode 0x{:X}", op),
+            Error::UnhandledInterrupt(v) => write!(f, "unhandled interrupt vector 0x{:X}", v),
+        }
+    }
+}
+
 enum Disp {
     None,
     i8(i8),
@@ -18,7 +43,7 @@ struct ModRM {
     disp: Disp,
 }
 impl ModRM {
-    fn parse(emu: &mut Emulator) -> ModRM {
+    fn parse(emu: &mut Emulator) -> Result<ModRM, Error> {
         let mut x = ModRM {
             mo: 0,
             re: 0,
@@ -26,33 +51,71 @@ impl ModRM {
             sib: None,
             disp: Disp::None,
         };
-        let code = emu.mem.read_u8(emu.eip);
+        let code = emu.mem.read_u8(emu.eip)?;
         x.mo = (code & 0b11000000) >> 6;
         x.re = (code & 0b00111000) >> 3;
         x.rm = (code & 0b00000111);
         emu.eip += 1;
 
         if (x.mo != 0b11 && x.rm == 0b100) {
-            x.sib = Some(emu.mem.read_u8(emu.eip));
+            x.sib = Some(emu.mem.read_u8(emu.eip)?);
             emu.eip += 1;
         }
 
+        // With a SIB byte, base==101 & Mod(00) means the base register is
+        // replaced by a disp32 (See Table 3.6).
+        let sib_disp32 = x.mo == 0b00 && x.rm == 0b100 && (x.sib.unwrap() & 0b111) == 0b101;
+
         // Mod(00) & RM(101) is a special case of disp32 (See Table 3.6)
-        if x.mo == 0b10 || (x.mo == 0b00 && x.rm == 0b101) {
-            x.disp = Disp::i32(emu.mem.read_i32(emu.eip));
+        if x.mo == 0b10 || (x.mo == 0b00 && x.rm == 0b101) || sib_disp32 {
+            x.disp = Disp::i32(emu.mem.read_i32(emu.eip)?);
             emu.eip += 4;
         } else if x.mo == 0b01 {
-            x.disp = Disp::i8(emu.mem.read_i8(emu.eip));
+            x.disp = Disp::i8(emu.mem.read_i8(emu.eip)?);
             emu.eip += 1;
         }
 
-        x
+        Ok(x)
     }
-    fn calc_memory_address(&self, emu: &Emulator) -> u32 {
-        match self.mo {
+    // Resolve the scale-index-base byte into `base + (index << scale)`. The
+    // disp (if any) is added by the caller; the only disp folded in here is
+    // the disp32 that replaces the base register when base==101 & Mod(00).
+    fn calc_sib_address(&self, emu: &Emulator) -> u32 {
+        let sib = self.sib.unwrap();
+        let scale = (sib & 0b11000000) >> 6;
+        let index = (sib & 0b00111000) >> 3;
+        let base = sib & 0b00000111;
+
+        // ESP (index==100) encodes "no index".
+        let index_term = if index == 0b100 {
+            0
+        } else {
+            emu.read_reg(index as usize) << scale
+        };
+        let base_term = if base == 0b101 && self.mo == 0b00 {
+            if let Disp::i32(x) = self.disp {
+                x as u32
+            } else {
+                unreachable!()
+            }
+        } else {
+            emu.read_reg(base as usize)
+        };
+        base_term + index_term
+    }
+    // Add a signed displacement to a base address (shared by all disp arms).
+    fn add_disp(base: u32, disp: i32) -> u32 {
+        if disp >= 0 {
+            base + disp as u32
+        } else {
+            base - (-disp) as u32
+        }
+    }
+    fn calc_memory_address(&self, emu: &Emulator) -> Result<u32, Error> {
+        let addr = match self.mo {
             0b00 => {
                 match self.rm {
-                    0b100 => unimplemented!(),
+                    0b100 => self.calc_sib_address(emu),
                     0b101 => {
                         // disp32
                         if let Disp::i32(x) = self.disp {
@@ -68,63 +131,56 @@ impl ModRM {
                 }
             }
             0b01 => {
-                match self.rm {
-                    0b100 => unimplemented!(),
-                    _ => {
-                        // [eax] + disp8
-                        if let Disp::i8(x) = self.disp {
-                            let base = emu.read_reg(self.rm as usize);
-                            if x >= 0 {
-                                base + x as u32
-                            } else {
-                                base - (-x) as u32
-                            }
-                        } else {
-                            unreachable!()
-                        }
-                    }
+                // [base] + disp8, base coming from a SIB byte or a register.
+                if let Disp::i8(x) = self.disp {
+                    let base = if self.rm == 0b100 {
+                        self.calc_sib_address(emu)
+                    } else {
+                        emu.read_reg(self.rm as usize)
+                    };
+                    ModRM::add_disp(base, x as i32)
+                } else {
+                    unreachable!()
                 }
             }
             0b10 => {
-                match self.rm {
-                    0b100 => unimplemented!(),
-                    _ => {
-                        // [eax] + disp32
-                        if let Disp::i32(x) = self.disp {
-                            let base = emu.read_reg(self.rm as usize);
-                            if x >= 0 {
-                                base + x as u32
-                            } else {
-                                base - (-x) as u32
-                            }
-                        } else {
-                            unreachable!()
-                        }
-                    }
+                // [base] + disp32, base coming from a SIB byte or a register.
+                if let Disp::i32(x) = self.disp {
+                    let base = if self.rm == 0b100 {
+                        self.calc_sib_address(emu)
+                    } else {
+                        emu.read_reg(self.rm as usize)
+                    };
+                    ModRM::add_disp(base, x)
+                } else {
+                    unreachable!()
                 }
             }
-            0b11 => unimplemented!(),
-            _ => unreachable!(),
-        }
+            // Register-direct (0b11) is resolved by read_u32/write_u32, so a
+            // memory-address request for it is an unsupported decode.
+            _ => return Err(Error::Decode),
+        };
+        Ok(addr)
     }
-    fn write_u32(&self, v: u32, emu: &mut Emulator) {
+    fn write_u32(&self, v: u32, emu: &mut Emulator) -> Result<(), Error> {
         match self.mo {
             0b11 => {
                 // eax
                 emu.write_reg(self.rm as usize, v);
+                Ok(())
             }
             _ => {
                 // [eax], [eax]+disp, disp
-                let addr = self.calc_memory_address(emu);
-                emu.mem.write_u32(addr, v);
+                let addr = self.calc_memory_address(emu)?;
+                emu.mem.write_u32(addr, v)
             }
         }
     }
-    fn read_u32(&self, emu: &mut Emulator) -> u32 {
+    fn read_u32(&self, emu: &mut Emulator) -> Result<u32, Error> {
         match self.mo {
-            0b11 => emu.read_reg(self.rm as usize),
+            0b11 => Ok(emu.read_reg(self.rm as usize)),
             _ => {
-                let addr = self.calc_memory_address(emu);
+                let addr = self.calc_memory_address(emu)?;
                 emu.mem.read_u32(addr)
             }
         }
@@ -132,25 +188,77 @@ impl ModRM {
 }
 
 trait Instruction {
-    fn exec(&self, emu: &mut Emulator);
+    fn exec(&self, emu: &mut Emulator) -> Result<(), Error>;
+}
+// A host-side handler for a software interrupt vector, dispatched from `int`.
+trait Trap {
+    fn handle(&self, emu: &mut Emulator);
+}
+// BIOS video services (int 0x10). Only the teletype sub-function (AH=0x0E),
+// which prints AL to the screen, is emulated.
+struct BiosVideo;
+impl Trap for BiosVideo {
+    fn handle(&self, emu: &mut Emulator) {
+        let eax = emu.read_reg(REG::EAX as usize);
+        let ah = (eax >> 8) & 0xff;
+        let al = (eax & 0xff) as u8;
+        match ah {
+            0x0E => {
+                let mut out = std::io::stdout();
+                out.write_all(&[al]).unwrap();
+                out.flush().unwrap();
+            }
+            _ => eprintln!("int 0x10: unsupported function AH={:X}", ah),
+        }
+    }
 }
 macro_rules! define_inst {
     ($name:ident, $emu:ident, $code:block) => {
         struct $name;
         impl Instruction for $name {
-            fn exec(&self, $emu: &mut Emulator) $code
+            fn exec(&self, $emu: &mut Emulator) -> Result<(), Error> {
+                $code
+                Ok(())
+            }
         }
     }
 }
 define_inst!(mov_r32_imm32, emu, {
-    let k = emu.mem.read_u8(emu.eip) - 0xB8;
-    let v = emu.mem.read_u32(emu.eip + 1);
+    let k = emu.mem.read_u8(emu.eip)? - 0xB8;
+    let v = emu.mem.read_u32(emu.eip + 1)?;
     emu.regs[k as usize] = v;
     emu.eip += 5;
 });
+// When `cond` holds, jump by the signed imm8 that follows the opcode, relative
+// to the end of the two-byte instruction; otherwise step over it.
+fn jcc(emu: &mut Emulator, cond: bool) -> Result<(), Error> {
+    let rel: i32 = if cond {
+        emu.mem.read_i8(emu.eip + 1)? as i32 + 2
+    } else {
+        2
+    };
+    if rel >= 0 {
+        emu.eip += rel as u32;
+    } else {
+        emu.eip -= (-rel) as u32;
+    }
+    Ok(())
+}
+define_inst!(jo, emu, { jcc(emu, emu.overflow())? });
+define_inst!(jno, emu, { jcc(emu, !emu.overflow())? });
+define_inst!(jc, emu, { jcc(emu, emu.carry())? });
+define_inst!(jnc, emu, { jcc(emu, !emu.carry())? });
+define_inst!(jz, emu, { jcc(emu, emu.zero())? });
+define_inst!(jnz, emu, { jcc(emu, !emu.zero())? });
+define_inst!(js, emu, { jcc(emu, emu.sign())? });
+define_inst!(jns, emu, { jcc(emu, !emu.sign())? });
+define_inst!(jl, emu, { jcc(emu, emu.sign() != emu.overflow())? });
+define_inst!(jge, emu, { jcc(emu, emu.sign() == emu.overflow())? });
+define_inst!(jle, emu, { jcc(emu, emu.zero() || (emu.sign() != emu.overflow()))? });
+define_inst!(jg, emu, { jcc(emu, !emu.zero() && (emu.sign() == emu.overflow()))? });
 define_inst!(short_jump, emu, {
-    let diff: i8 = emu.mem.read_i8(emu.eip + 1);
-    let d = diff + 2;
+    let diff: i8 = emu.mem.read_i8(emu.eip + 1)?;
+    let d = diff as i32 + 2;
     if d >= 0 {
         emu.eip += d as u32;
     } else {
@@ -158,7 +266,7 @@ define_inst!(short_jump, emu, {
     }
 });
 define_inst!(near_jump, emu, {
-    let diff: i32 = emu.mem.read_i32(emu.eip + 1);
+    let diff: i32 = emu.mem.read_i32(emu.eip + 1)?;
     let d = diff + 5;
     if d >= 0 {
         emu.eip += d as u32;
@@ -168,111 +276,111 @@ define_inst!(near_jump, emu, {
 });
 define_inst!(mov_rm32_imm32, emu, {
     emu.eip += 1;
-    let modrm = ModRM::parse(emu);
-    let v = emu.mem.read_u32(emu.eip);
+    let modrm = ModRM::parse(emu)?;
+    let v = emu.mem.read_u32(emu.eip)?;
     emu.eip += 4;
-    modrm.write_u32(v, emu);
+    modrm.write_u32(v, emu)?;
 });
 define_inst!(mov_rm32_r32, emu, {
     emu.eip += 1;
-    let modrm = ModRM::parse(emu);
+    let modrm = ModRM::parse(emu)?;
     let v = emu.read_reg(modrm.re as usize);
-    modrm.write_u32(v, emu);
+    modrm.write_u32(v, emu)?;
 });
 define_inst!(mov_r32_rm32, emu, {
     emu.eip += 1;
-    let modrm = ModRM::parse(emu);
-    let v = modrm.read_u32(emu);
+    let modrm = ModRM::parse(emu)?;
+    let v = modrm.read_u32(emu)?;
     emu.write_reg(modrm.re as usize, v);
 });
 define_inst!(add_rm32_r32, emu, {
     emu.eip += 1;
-    let modrm = ModRM::parse(emu);
-    let a = modrm.read_u32(emu);
+    let modrm = ModRM::parse(emu)?;
+    let a = modrm.read_u32(emu)?;
     let b = emu.read_reg(modrm.re as usize);
     let c = a + b;
-    modrm.write_u32(c, emu);
+    modrm.write_u32(c, emu)?;
     // TODO eflags
 });
 define_inst!(cmp_r32_rm32, emu, {
     emu.eip += 1;
-    let modrm = ModRM::parse(emu);
+    let modrm = ModRM::parse(emu)?;
     let a = emu.read_reg(modrm.re as usize);
-    let b = modrm.read_u32(emu);
+    let b = modrm.read_u32(emu)?;
     let c = a as u64 - b as u64;
     update_eflags(&mut emu.eflags, a, b, c);
 });
 define_inst!(code_83, emu, {
     emu.eip += 1;
-    let modrm = ModRM::parse(emu);
+    let modrm = ModRM::parse(emu)?;
     match modrm.re {
         0 => {
             // add_rm32_imm8
-            let a = modrm.read_u32(emu);
-            let b = emu.mem.read_i8(emu.eip) as u32;
+            let a = modrm.read_u32(emu)?;
+            let b = emu.mem.read_i8(emu.eip)? as u32;
             emu.eip += 1;
             let c = a as i64 + b as i64;
-            modrm.write_u32(c as u32, emu);
+            modrm.write_u32(c as u32, emu)?;
             update_eflags(&mut emu.eflags, a, b, c as u64);
         }
         5 => {
             // sub_rm32_imm8
-            let a = modrm.read_u32(emu);
-            let b = emu.mem.read_i8(emu.eip) as u32;
+            let a = modrm.read_u32(emu)?;
+            let b = emu.mem.read_i8(emu.eip)? as u32;
             emu.eip += 1;
             let c = a as i64 - b as i64;
-            modrm.write_u32(c as u32, emu);
+            modrm.write_u32(c as u32, emu)?;
             update_eflags(&mut emu.eflags, a, b, c as u64);
         }
         7 => {
             // cmp_rm32_imm8
-            let a = modrm.read_u32(emu);
-            let b = emu.mem.read_i8(emu.eip) as u32;
+            let a = modrm.read_u32(emu)?;
+            let b = emu.mem.read_i8(emu.eip)? as u32;
             emu.eip += 1;
             let c = a as i64 - b as i64;
             update_eflags(&mut emu.eflags, a, b, c as u64);
         }
-        _ => unreachable!(),
+        _ => return Err(Error::Decode),
     }
 });
 define_inst!(code_ff, emu, {
     emu.eip += 1;
-    let modrm = ModRM::parse(emu);
+    let modrm = ModRM::parse(emu)?;
     match modrm.re {
         0 => {
             // inc_rm32
-            let a = modrm.read_u32(emu);
-            modrm.write_u32(a + 1, emu);
+            let a = modrm.read_u32(emu)?;
+            modrm.write_u32(a + 1, emu)?;
         }
-        _ => unimplemented!(),
+        _ => return Err(Error::Decode),
     }
 });
 define_inst!(push_imm8, emu, {
-    let v = emu.mem.read_u8(emu.eip + 1);
-    emu.push(v as u32);
+    let v = emu.mem.read_u8(emu.eip + 1)?;
+    emu.push(v as u32)?;
     emu.eip += 2;
 });
 define_inst!(push_imm32, emu, {
-    let v = emu.mem.read_u32(emu.eip + 1);
-    emu.push(v);
+    let v = emu.mem.read_u32(emu.eip + 1)?;
+    emu.push(v)?;
     emu.eip += 5;
 });
 define_inst!(push_r32, emu, {
-    let reg = emu.mem.read_u8(emu.eip) - 0x50;
+    let reg = emu.mem.read_u8(emu.eip)? - 0x50;
     let v = emu.read_reg(reg as usize);
-    emu.push(v);
+    emu.push(v)?;
     emu.eip += 1;
 });
 define_inst!(pop_r32, emu, {
-    let reg = emu.mem.read_u8(emu.eip) - 0x58;
-    let v = emu.pop();
+    let reg = emu.mem.read_u8(emu.eip)? - 0x58;
+    let v = emu.pop()?;
     emu.write_reg(reg as usize, v);
     emu.eip += 1;
 });
 define_inst!(call_rel32, emu, {
-    let diff = emu.mem.read_i32(emu.eip + 1);
+    let diff = emu.mem.read_i32(emu.eip + 1)?;
     // Push the address after call
-    emu.push(emu.eip + 5);
+    emu.push(emu.eip + 5)?;
     let d = diff + 5;
     if d >= 0 {
         emu.eip += d as u32;
@@ -285,12 +393,28 @@ define_inst!(leave, emu, {
     let ebp = emu.read_reg(REG::EBP as usize);
     emu.write_reg(REG::ESP as usize, ebp);
     // pop ebp
-    let v = emu.pop();
+    let v = emu.pop()?;
     emu.write_reg(REG::EBP as usize, v);
     emu.eip += 1;
 });
 define_inst!(ret, emu, {
-    emu.eip = emu.pop();
+    let ret_addr = emu.pop()?;
+    // A ret to the sentinel 0x0 (the initial, zeroed stack) is the clean exit
+    // of top-level boot code, so stop rather than executing address 0.
+    if ret_addr == 0 {
+        emu.halted = true;
+    } else {
+        emu.eip = ret_addr;
+    }
+});
+define_inst!(hlt, emu, {
+    emu.halted = true;
+    emu.eip += 1;
+});
+define_inst!(int_imm8, emu, {
+    let vector = emu.mem.read_u8(emu.eip + 1)?;
+    emu.eip += 2;
+    emu.handle_interrupt(vector)?;
 });
 enum REG {
     EAX,
@@ -317,25 +441,39 @@ impl Memory {
         let buf = &mut self.v[at..at + n];
         buf.copy_from_slice(&bin)
     }
-    fn read_u8(&self, i: u32) -> u8 {
+    // Ensure [i, i+n) lies within the backing store.
+    fn bounds(&self, i: u32, n: u32) -> Result<(), Error> {
+        if i as usize + n as usize > self.v.len() {
+            Err(Error::Memory(i))
+        } else {
+            Ok(())
+        }
+    }
+    fn read_u8(&self, i: u32) -> Result<u8, Error> {
+        self.bounds(i, 1)?;
         let mut buf = &self.v[i as usize..];
-        buf.read_u8().unwrap()
+        Ok(buf.read_u8().unwrap())
     }
-    fn read_i8(&self, i: u32) -> i8 {
+    fn read_i8(&self, i: u32) -> Result<i8, Error> {
+        self.bounds(i, 1)?;
         let mut buf = &self.v[i as usize..];
-        buf.read_i8().unwrap()
+        Ok(buf.read_i8().unwrap())
     }
-    fn read_u32(&self, i: u32) -> u32 {
+    fn read_u32(&self, i: u32) -> Result<u32, Error> {
+        self.bounds(i, 4)?;
         let mut buf = &self.v[i as usize..];
-        buf.read_u32::<LittleEndian>().unwrap()
+        Ok(buf.read_u32::<LittleEndian>().unwrap())
     }
-    fn read_i32(&self, i: u32) -> i32 {
+    fn read_i32(&self, i: u32) -> Result<i32, Error> {
+        self.bounds(i, 4)?;
         let mut buf = &self.v[i as usize..];
-        buf.read_i32::<LittleEndian>().unwrap()
+        Ok(buf.read_i32::<LittleEndian>().unwrap())
     }
-    fn write_u32(&mut self, i: u32, v: u32) {
+    fn write_u32(&mut self, i: u32, v: u32) -> Result<(), Error> {
+        self.bounds(i, 4)?;
         let mut buf = &mut self.v[i as usize..];
-        buf.write_u32::<LittleEndian>(v).unwrap()
+        buf.write_u32::<LittleEndian>(v).unwrap();
+        Ok(())
     }
 }
 enum EFLAGS_SHIFT {
@@ -381,6 +519,194 @@ fn update_eflags(out: &mut u32, x: u32, y: u32, z: u64) {
         unset(out, OVERFLOW as u32);
     }
 }
+const REG32: [&str; 8] = ["eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi"];
+
+// A read-only cursor over memory, used by the disassembler so it can decode an
+// instruction without disturbing the emulator's EIP.
+struct Cursor<'a> {
+    emu: &'a Emulator,
+    at: u32,
+}
+impl<'a> Cursor<'a> {
+    // The disassembler is best-effort: out-of-range reads render as zero
+    // rather than faulting the way execution does.
+    fn read_u8(&mut self) -> u8 {
+        let v = self.emu.mem.read_u8(self.at).unwrap_or(0);
+        self.at += 1;
+        v
+    }
+    fn read_i8(&mut self) -> i8 {
+        let v = self.emu.mem.read_i8(self.at).unwrap_or(0);
+        self.at += 1;
+        v
+    }
+    fn read_u32(&mut self) -> u32 {
+        let v = self.emu.mem.read_u32(self.at).unwrap_or(0);
+        self.at += 4;
+        v
+    }
+    fn read_i32(&mut self) -> i32 {
+        let v = self.emu.mem.read_i32(self.at).unwrap_or(0);
+        self.at += 4;
+        v
+    }
+}
+// Render a signed displacement as `+0x4` / `-0x4`.
+fn fmt_disp(d: i64) -> String {
+    if d >= 0 {
+        format!("+0x{:X}", d)
+    } else {
+        format!("-0x{:X}", -d)
+    }
+}
+// Render a signed immediate operand as `0x4` / `-0x4`.
+fn fmt_imm(v: i64) -> String {
+    if v >= 0 {
+        format!("0x{:X}", v)
+    } else {
+        format!("-0x{:X}", -v)
+    }
+}
+// Decode a ModRM byte (and its SIB/disp) into an Intel-syntax operand string
+// plus the reg field. Mirrors `ModRM::parse`/`calc_memory_address` but reads
+// through a cursor so it never mutates EIP.
+fn render_modrm(cur: &mut Cursor) -> (String, u8) {
+    let code = cur.read_u8();
+    let mo = code >> 6;
+    let re = (code >> 3) & 0b111;
+    let rm = code & 0b111;
+
+    let sib = if mo != 0b11 && rm == 0b100 {
+        Some(cur.read_u8())
+    } else {
+        None
+    };
+
+    let sib_disp32 = mo == 0b00 && rm == 0b100 && sib.map_or(false, |s| s & 0b111 == 0b101);
+    let disp: i64 = if mo == 0b10 || (mo == 0b00 && rm == 0b101) || sib_disp32 {
+        cur.read_i32() as i64
+    } else if mo == 0b01 {
+        cur.read_i8() as i64
+    } else {
+        0
+    };
+
+    let text = if mo == 0b11 {
+        REG32[rm as usize].to_string()
+    } else if rm == 0b100 {
+        let sib = sib.unwrap();
+        let scale = sib >> 6;
+        let index = (sib >> 3) & 0b111;
+        let base = sib & 0b111;
+        let mut parts = String::new();
+        let disp32_base = base == 0b101 && mo == 0b00;
+        if disp32_base {
+            parts.push_str(&format!("0x{:X}", disp as u32));
+        } else {
+            parts.push_str(REG32[base as usize]);
+        }
+        if index != 0b100 {
+            parts.push('+');
+            parts.push_str(REG32[index as usize]);
+            if scale > 0 {
+                parts.push_str(&format!("*{}", 1 << scale));
+            }
+        }
+        if !disp32_base && disp != 0 {
+            parts.push_str(&fmt_disp(disp));
+        }
+        format!("dword [{}]", parts)
+    } else if mo == 0b00 && rm == 0b101 {
+        format!("dword [0x{:X}]", disp as u32)
+    } else if disp != 0 {
+        format!("dword [{}{}]", REG32[rm as usize], fmt_disp(disp))
+    } else {
+        format!("dword [{}]", REG32[rm as usize])
+    };
+
+    (text, re)
+}
+// Render the instruction at `eip` as a single line of Intel assembly.
+fn disassemble(emu: &Emulator, eip: u32) -> String {
+    let mut cur = Cursor { emu, at: eip };
+    let op = cur.read_u8();
+    match op {
+        0x01 => {
+            let (rm, re) = render_modrm(&mut cur);
+            format!("add {}, {}", rm, REG32[re as usize])
+        }
+        0x50..=0x57 => format!("push {}", REG32[(op - 0x50) as usize]),
+        0x58..=0x5F => format!("pop {}", REG32[(op - 0x58) as usize]),
+        0x68 => format!("push 0x{:X}", cur.read_u32()),
+        0x6A => format!("push 0x{:X}", cur.read_u8()),
+        0x70..=0x7F => {
+            let mnemonic = match op {
+                0x70 => "jo",
+                0x71 => "jno",
+                0x72 => "jc",
+                0x73 => "jnc",
+                0x74 => "jz",
+                0x75 => "jnz",
+                0x78 => "js",
+                0x79 => "jns",
+                0x7C => "jl",
+                0x7D => "jge",
+                0x7E => "jle",
+                0x7F => "jg",
+                _ => "j?",
+            };
+            let rel = cur.read_i8() as i64;
+            format!("{} 0x{:X}", mnemonic, (eip as i64 + 2 + rel) as u32)
+        }
+        0x83 => {
+            let (rm, re) = render_modrm(&mut cur);
+            let mnemonic = match re {
+                0 => "add",
+                5 => "sub",
+                7 => "cmp",
+                _ => "?",
+            };
+            format!("{} {}, {}", mnemonic, rm, fmt_imm(cur.read_i8() as i64))
+        }
+        0x89 => {
+            let (rm, re) = render_modrm(&mut cur);
+            format!("mov {}, {}", rm, REG32[re as usize])
+        }
+        0x8B => {
+            let (rm, re) = render_modrm(&mut cur);
+            format!("mov {}, {}", REG32[re as usize], rm)
+        }
+        0xB8..=0xBF => format!("mov {}, 0x{:X}", REG32[(op - 0xB8) as usize], cur.read_u32()),
+        0xC3 => "ret".to_string(),
+        0xC7 => {
+            let (rm, _) = render_modrm(&mut cur);
+            format!("mov {}, 0x{:X}", rm, cur.read_u32())
+        }
+        0xC9 => "leave".to_string(),
+        0xCD => format!("int 0x{:X}", cur.read_u8()),
+        0xE8 => {
+            let rel = cur.read_i32() as i64;
+            format!("call 0x{:X}", (eip as i64 + 5 + rel) as u32)
+        }
+        0xE9 => {
+            let rel = cur.read_i32() as i64;
+            format!("jmp 0x{:X}", (eip as i64 + 5 + rel) as u32)
+        }
+        0xEB => {
+            let rel = cur.read_i8() as i64;
+            format!("jmp 0x{:X}", (eip as i64 + 2 + rel) as u32)
+        }
+        0xF4 => "hlt".to_string(),
+        0xFF => {
+            let (rm, re) = render_modrm(&mut cur);
+            match re {
+                0 => format!("inc {}", rm),
+                _ => format!("(ff /{})", re),
+            }
+        }
+        _ => format!("db 0x{:X}", op),
+    }
+}
 struct Emulator {
     regs: Vec<u32>,
     eflags: u32,
@@ -390,6 +716,24 @@ struct Emulator {
 
     // code -> inst
     insts: HashMap<u8, Arc<dyn Instruction>>,
+    // interrupt vector -> trap handler
+    traps: HashMap<u8, Arc<dyn Trap>>,
+
+    // Addresses the debugger should stop at before executing.
+    breakpoints: HashSet<u32>,
+    // Print a register dump on every step (true) or run silently until a
+    // breakpoint is reached (false).
+    trace_only: bool,
+    // Set by exec when it returned because a breakpoint was hit.
+    breakpoint_occurred: bool,
+    // Print an Intel-syntax disassembly line before each executed instruction.
+    trace: bool,
+    // Set by `hlt`, a `ret` to address 0, or a fault to end the run.
+    halted: bool,
+    // Number of instructions retired so far.
+    cycles: u64,
+    // Optional upper bound on retired instructions, to bound runaway programs.
+    cycle_budget: Option<u64>,
 }
 impl Emulator {
     fn new(mem_size: u32, eip: u32, esp: u32) -> Self {
@@ -403,6 +747,18 @@ impl Emulator {
             insts.insert(0x58 + i, Arc::new(pop_r32));
         }
         insts.insert(0x68, Arc::new(push_imm32));
+        insts.insert(0x70, Arc::new(jo));
+        insts.insert(0x71, Arc::new(jno));
+        insts.insert(0x72, Arc::new(jc));
+        insts.insert(0x73, Arc::new(jnc));
+        insts.insert(0x74, Arc::new(jz));
+        insts.insert(0x75, Arc::new(jnz));
+        insts.insert(0x78, Arc::new(js));
+        insts.insert(0x79, Arc::new(jns));
+        insts.insert(0x7C, Arc::new(jl));
+        insts.insert(0x7D, Arc::new(jge));
+        insts.insert(0x7E, Arc::new(jle));
+        insts.insert(0x7F, Arc::new(jg));
         insts.insert(0x6a, Arc::new(push_imm8));
         insts.insert(0x83, Arc::new(code_83));
         insts.insert(0x89, Arc::new(mov_rm32_r32));
@@ -411,19 +767,32 @@ impl Emulator {
             insts.insert(0xB8 + i, Arc::new(mov_r32_imm32));
         }
         insts.insert(0xC3, Arc::new(ret));
+        insts.insert(0xCD, Arc::new(int_imm8));
         insts.insert(0xC7, Arc::new(mov_rm32_imm32));
         insts.insert(0xC9, Arc::new(leave));
         insts.insert(0xE8, Arc::new(call_rel32));
         insts.insert(0xE9, Arc::new(near_jump));
         insts.insert(0xEB, Arc::new(short_jump));
+        insts.insert(0xF4, Arc::new(hlt));
         insts.insert(0xFF, Arc::new(code_ff));
 
+        let mut traps: HashMap<u8, Arc<dyn Trap>> = HashMap::new();
+        traps.insert(0x10, Arc::new(BiosVideo));
+
         let mut x = Emulator {
             regs: vec![0; REG::COUNT as usize],
             eflags: 0,
             eip,
             mem: Memory::new(mem_size),
             insts,
+            traps,
+            breakpoints: HashSet::new(),
+            trace_only: true,
+            breakpoint_occurred: false,
+            trace: false,
+            halted: false,
+            cycles: 0,
+            cycle_budget: None,
         };
         x.regs[REG::ESP as usize] = esp;
         x
@@ -431,19 +800,47 @@ impl Emulator {
     fn read_reg(&self, i: usize) -> u32 {
         self.regs[i]
     }
+    // Dispatch a software interrupt to its registered trap handler. An
+    // unregistered vector surfaces as a recoverable fault, distinct from the
+    // `hlt`-driven clean halt, so a host can tell the two apart.
+    fn handle_interrupt(&mut self, vector: u8) -> Result<(), Error> {
+        match self.traps.get(&vector) {
+            Some(trap) => {
+                let trap = Arc::clone(trap);
+                trap.handle(self);
+                Ok(())
+            }
+            None => Err(Error::UnhandledInterrupt(vector)),
+        }
+    }
+    fn flag(&self, shift: EFLAGS_SHIFT) -> bool {
+        (self.eflags >> (shift as u32)) & 1 > 0
+    }
+    fn carry(&self) -> bool {
+        self.flag(EFLAGS_SHIFT::CARRY)
+    }
+    fn zero(&self) -> bool {
+        self.flag(EFLAGS_SHIFT::ZERO)
+    }
+    fn sign(&self) -> bool {
+        self.flag(EFLAGS_SHIFT::SIGN)
+    }
+    fn overflow(&self) -> bool {
+        self.flag(EFLAGS_SHIFT::OVERFLOW)
+    }
     fn write_reg(&mut self, i: usize, v: u32) {
         self.regs[i] = v;
     }
-    fn push(&mut self, v: u32) {
+    fn push(&mut self, v: u32) -> Result<(), Error> {
         let new_esp = self.read_reg(REG::ESP as usize) - 4;
         self.write_reg(REG::ESP as usize, new_esp);
-        self.mem.write_u32(new_esp, v);
+        self.mem.write_u32(new_esp, v)
     }
-    fn pop(&mut self) -> u32 {
+    fn pop(&mut self) -> Result<u32, Error> {
         let cur_esp = self.read_reg(REG::ESP as usize);
-        let v = self.mem.read_u32(cur_esp);
+        let v = self.mem.read_u32(cur_esp)?;
         self.write_reg(REG::ESP as usize, cur_esp + 4);
-        v
+        Ok(v)
     }
     fn print_registers(&self) {
         eprintln!("EAX = {:X}", self.regs[REG::EAX as usize]);
@@ -456,44 +853,219 @@ impl Emulator {
         eprintln!("EDI = {:X}", self.regs[REG::EDI as usize]);
         eprintln!("EIP = {:X}", self.eip);
     }
-    fn exec(&mut self) {
+    // Execute the single instruction at EIP and retire one cycle. Faults are
+    // returned to the caller rather than aborting the process.
+    fn step(&mut self) -> Result<(), Error> {
+        let opcode = self.mem.read_u8(self.eip)?;
+        let inst = match self.insts.get(&opcode) {
+            Some(inst) => Arc::clone(inst),
+            None => return Err(Error::UnknownOpcode(opcode)),
+        };
+        if self.trace {
+            eprintln!("{:08X}: {}", self.eip, disassemble(self, self.eip));
+        } else if self.trace_only {
+            eprintln!("op: {:X}", opcode);
+        }
+        inst.exec(self)?;
+        self.cycles += 1;
+        Ok(())
+    }
+    fn exec(&mut self) -> Result<(), Error> {
+        self.breakpoint_occurred = false;
         let mut step = 0;
-        while self.eip < MEMORY_SIZE {
-            step += 1;
-            eprintln!("----------");
-            eprintln!("STEP {}", step);
-            self.print_registers();
-
-            let opcode = self.mem.read_u8(self.eip);
-            if let Some(inst) = self.insts.get(&opcode) {
-                eprintln!("op: {:X}", opcode);
-                let inst = Arc::clone(&inst);
-                inst.exec(self);
-            } else {
-                eprintln!("op({:X}) not implemented", opcode);
-                break;
+        while self.eip < MEMORY_SIZE && !self.halted {
+            if let Some(budget) = self.cycle_budget {
+                if self.cycles >= budget {
+                    eprintln!("cycle budget {} exhausted", budget);
+                    break;
+                }
             }
-
-            if self.eip == 0x00 {
+            if self.trace_only {
+                step += 1;
                 eprintln!("----------");
-                eprintln!("END");
+                eprintln!("STEP {}", step);
                 self.print_registers();
+            }
+            let at = self.eip;
+            if let Err(e) = self.step() {
+                // Report the fault with the offending EIP/opcode and stop.
+                let op = self.mem.read_u8(at).unwrap_or(0);
+                eprintln!("fault at EIP=0x{:X} (opcode 0x{:X}): {}", at, op, e);
+                self.halted = true;
+                return Err(e);
+            }
+            if self.halted {
+                break;
+            }
+            if self.breakpoints.contains(&self.eip) {
+                self.breakpoint_occurred = true;
+                eprintln!("breakpoint at {:X}", self.eip);
                 break;
             }
         }
+        if self.trace_only {
+            eprintln!("----------");
+            eprintln!("END");
+            self.print_registers();
+        }
+        eprintln!("cycles: {}", self.cycles);
+        Ok(())
+    }
+}
+// Parse a `0x`-prefixed (or bare) hex number used throughout the REPL.
+fn parse_addr(s: &str) -> Option<u32> {
+    let s = s.trim_start_matches("0x");
+    u32::from_str_radix(s, 16).ok()
+}
+// Interactive front-end around an `Emulator`. It drives the step loop from a
+// small command dispatcher instead of blindly dumping every register.
+struct Debugger {
+    emu: Emulator,
+    last_command: Vec<String>,
+    repeat: u32,
+}
+impl Debugger {
+    fn new(emu: Emulator) -> Self {
+        Debugger {
+            emu,
+            last_command: Vec::new(),
+            repeat: 0,
+        }
+    }
+    fn run(&mut self) {
+        // The REPL drives the loop step-by-step, so keep it quiet by default.
+        self.emu.trace_only = false;
+        let stdin = std::io::stdin();
+        loop {
+            eprint!("(x86emu) ");
+            std::io::stderr().flush().unwrap();
+            let mut line = String::new();
+            if stdin.read_line(&mut line).unwrap() == 0 {
+                break;
+            }
+            let args: Vec<&str> = line.split_whitespace().collect();
+            if args.is_empty() {
+                continue;
+            }
+            // A bare number repeats the previous command that many times.
+            if let Ok(n) = args[0].parse::<u32>() {
+                self.repeat = n;
+                let last = self.last_command.clone();
+                let last: Vec<&str> = last.iter().map(|s| s.as_str()).collect();
+                for _ in 0..self.repeat {
+                    self.dispatch(&last);
+                }
+                continue;
+            }
+            self.last_command = args.iter().map(|s| s.to_string()).collect();
+            self.dispatch(&args);
+        }
+    }
+    fn dispatch(&mut self, args: &[&str]) {
+        if args.is_empty() {
+            return;
+        }
+        match args[0] {
+            "b" | "break" => match args.get(1).and_then(|s| parse_addr(s)) {
+                Some(addr) => {
+                    self.emu.breakpoints.insert(addr);
+                    eprintln!("breakpoint set at {:X}", addr);
+                }
+                None => eprintln!("usage: b <addr>"),
+            },
+            "s" | "step" => {
+                if let Err(e) = self.emu.step() {
+                    eprintln!("fault: {}", e);
+                }
+                self.emu.print_registers();
+            }
+            "c" | "continue" => {
+                match self.emu.exec() {
+                    Ok(()) if self.emu.breakpoint_occurred => {
+                        eprintln!("stopped at breakpoint {:X}", self.emu.eip)
+                    }
+                    Ok(()) => {}
+                    Err(e) => eprintln!("fault: {}", e),
+                }
+            }
+            "mem" => {
+                let addr = args.get(1).and_then(|s| parse_addr(s));
+                let len = args.get(2).and_then(|s| parse_addr(s));
+                match (addr, len) {
+                    (Some(addr), Some(len)) => self.dump_memory(addr, len),
+                    _ => eprintln!("usage: mem <addr> <len>"),
+                }
+            }
+            "stack" => self.dump_stack(),
+            "q" | "quit" => std::process::exit(0),
+            other => eprintln!("unknown command: {}", other),
+        }
+    }
+    fn dump_memory(&self, addr: u32, len: u32) {
+        for i in 0..len {
+            if i % 16 == 0 {
+                if i > 0 {
+                    eprintln!();
+                }
+                eprint!("{:08X}:", addr + i);
+            }
+            match self.emu.mem.read_u8(addr + i) {
+                Ok(b) => eprint!(" {:02X}", b),
+                Err(e) => {
+                    eprintln!(" <{}>", e);
+                    return;
+                }
+            }
+        }
+        eprintln!();
+    }
+    fn dump_stack(&self) {
+        let esp = self.emu.read_reg(REG::ESP as usize);
+        for i in 0..16 {
+            let at = esp + i * 4;
+            match self.emu.mem.read_u32(at) {
+                Ok(v) => eprintln!("{:08X}: {:08X}", at, v),
+                Err(e) => {
+                    eprintln!("{:08X}: <{}>", at, e);
+                    return;
+                }
+            }
+        }
     }
 }
 #[derive(Clap)]
 struct Opts {
     bin_file: String,
+    // Print an Intel-syntax disassembly trace of every executed instruction.
+    #[clap(long)]
+    trace: bool,
+    // Terminate after this many retired instructions (0 = unbounded).
+    #[clap(long, default_value = "0")]
+    max_cycles: u64,
+    // Run to completion without the interactive debugger REPL.
+    #[clap(long)]
+    run: bool,
 }
 fn main() {
     let opts = Opts::parse();
 
     let mut emu = Emulator::new(MEMORY_SIZE, 0x7c00, 0x7c00);
+    emu.trace = opts.trace;
+    if opts.max_cycles > 0 {
+        emu.cycle_budget = Some(opts.max_cycles);
+    }
 
     let bin = std::fs::read(opts.bin_file).expect("failed to read program");
     emu.mem.load_bin(&bin, 0x7c00);
 
-    emu.exec();
+    // `--trace`/`--max-cycles` only make sense against a running program, so a
+    // non-interactive run is selected by `--run` or implied by `--trace`.
+    if opts.run || opts.trace {
+        if let Err(e) = emu.exec() {
+            eprintln!("fault: {}", e);
+        }
+    } else {
+        let mut dbg = Debugger::new(emu);
+        dbg.run();
+    }
 }